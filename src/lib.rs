@@ -1,13 +1,33 @@
+mod metrics;
+
+pub use metrics::{encode as encode_metrics, Metrics};
+
+use futures::prelude::*;
 use libp2p::{
-    floodsub::{Floodsub, FloodsubEvent, Topic},
+    core::upgrade::{read_length_prefixed, write_length_prefixed},
+    gossipsub::{
+        Gossipsub, GossipsubConfigBuilder, GossipsubEvent, GossipsubMessage, IdentTopic as Topic,
+        MessageAcceptance, MessageAuthenticity, MessageId, ValidationMode,
+    },
     identity,
     mdns::{Mdns, MdnsEvent},
-    swarm::NetworkBehaviourEventProcess,
+    request_response::{
+        ProtocolName, ProtocolSupport, RequestResponse, RequestResponseCodec,
+        RequestResponseConfig, RequestResponseEvent, RequestResponseMessage,
+    },
+    swarm::{NetworkBehaviourEventProcess, Toggle},
     NetworkBehaviour, PeerId,
 };
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::iter;
+use std::time::Duration;
+
+const HISTORY_CAPACITY: usize = 50;
 
 pub static KEYS: Lazy<identity::Keypair> = Lazy::new(identity::Keypair::generate_ed25519);
 pub static PEER_ID: Lazy<PeerId> = Lazy::new(|| PeerId::from(KEYS.public()));
@@ -24,33 +44,136 @@ struct SayHello {
     hello: bool,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredMessage {
+    author: String,
+    text: String,
+    seq: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
-struct IntroduceMyself {
-    name: String,
+struct HistoryRequest {
+    since: Option<u64>,
     receiver: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct HistoryResponse {
+    receiver: String,
+    messages: Vec<StoredMessage>,
+}
+
+/// Requests carried over the `/chat/direct/1.0.0` one-to-one protocol.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum DirectMessage {
+    Chat { body: String },
+    Introduce { name: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ack;
+
+#[derive(Debug, Clone, Default)]
+pub struct DirectProtocol();
+
+impl ProtocolName for DirectProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        "/chat/direct/1.0.0".as_bytes()
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct DirectCodec();
+
+#[async_trait::async_trait]
+impl RequestResponseCodec for DirectCodec {
+    type Protocol = DirectProtocol;
+    type Request = DirectMessage;
+    type Response = Ack;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &DirectProtocol,
+        io: &mut T,
+    ) -> io::Result<DirectMessage>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, 1_000_000).await?;
+        serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn read_response<T>(&mut self, _: &DirectProtocol, io: &mut T) -> io::Result<Ack>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let data = read_length_prefixed(io, 1_000_000).await?;
+        serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &DirectProtocol,
+        io: &mut T,
+        request: DirectMessage,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let data = serde_json::to_vec(&request)?;
+        write_length_prefixed(io, data).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &DirectProtocol,
+        io: &mut T,
+        response: Ack,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        let data = serde_json::to_vec(&response)?;
+        write_length_prefixed(io, data).await
+    }
+}
+
 #[derive(NetworkBehaviour)]
 pub struct AppBehaviour {
-    pub floodsub: Floodsub,
-    pub mdns: Mdns,
+    pub gossipsub: Gossipsub,
+    pub mdns: Toggle<Mdns>,
+    pub direct: RequestResponse<DirectCodec>,
     #[behaviour(ignore)]
     connected: HashMap<String, String>,
     #[behaviour(ignore)]
     name: String,
+    #[behaviour(ignore)]
+    history: VecDeque<StoredMessage>,
+    #[behaviour(ignore)]
+    next_seq: u64,
+    #[behaviour(ignore)]
+    banned: HashSet<PeerId>,
+    #[behaviour(ignore)]
+    metrics: Metrics,
 }
 impl NetworkBehaviourEventProcess<MdnsEvent> for AppBehaviour {
     fn inject_event(&mut self, event: MdnsEvent) {
         match event {
             MdnsEvent::Discovered(discovered_list) => {
+                self.metrics.record_mdns_discovered(discovered_list.len());
                 for (peer, _addr) in discovered_list {
-                    self.floodsub.add_node_to_partial_view(peer);
+                    if self.banned.contains(&peer) {
+                        continue;
+                    }
+                    self.gossipsub.add_explicit_peer(&peer);
                 }
             }
             MdnsEvent::Expired(expired_list) => {
+                self.metrics.record_mdns_expired(expired_list.len());
                 for (peer, _addr) in expired_list {
-                    if !self.mdns.has_node(&peer) {
-                        self.floodsub.remove_node_from_partial_view(&peer);
+                    let still_known = self.mdns.as_ref().map_or(false, |m| m.has_node(&peer));
+                    if !still_known {
+                        self.gossipsub.remove_explicit_peer(&peer);
                     }
                 }
             }
@@ -58,62 +181,276 @@ impl NetworkBehaviourEventProcess<MdnsEvent> for AppBehaviour {
     }
 }
 
-impl NetworkBehaviourEventProcess<FloodsubEvent> for AppBehaviour {
-    fn inject_event(&mut self, event: FloodsubEvent) {
-        if let FloodsubEvent::Message(msg) = event {
-            if let Ok(resp) = serde_json::from_slice::<ChatMessage>(&msg.data) {
-                if let Some(author) = self.connected.get(&msg.source.to_string()) {
-                    println!("{}: {}", author, resp.message);
-                } else {
-                    println!("Unknown: {}", resp.message);
-                };
-            } else if let Ok(resp) = serde_json::from_slice::<IntroduceMyself>(&msg.data) {
-                if resp.receiver == (*PEER_ID).to_string() {
-                    println!("{} is in chat room", resp.name);
-                    self.connected.insert(msg.source.to_string(), resp.name);
-                }
-            } else if let Ok(resp) = serde_json::from_slice::<SayHello>(&msg.data) {
+impl NetworkBehaviourEventProcess<GossipsubEvent> for AppBehaviour {
+    fn inject_event(&mut self, event: GossipsubEvent) {
+        if let GossipsubEvent::Message {
+            propagation_source,
+            message_id,
+            message,
+        } = event
+        {
+            let source_peer = message.source.unwrap_or(propagation_source);
+            if self.banned.contains(&source_peer) {
+                let _ = self.gossipsub.report_message_validation_result(
+                    &message_id,
+                    &propagation_source,
+                    MessageAcceptance::Reject,
+                );
+                return;
+            }
+            let source = source_peer.to_string();
+            let acceptance = if let Ok(resp) = serde_json::from_slice::<ChatMessage>(&message.data)
+            {
+                self.metrics.record_received("ChatMessage");
+                let author = self
+                    .connected
+                    .get(&source)
+                    .cloned()
+                    .unwrap_or_else(|| "Unknown".to_string());
+                println!("{}: {}", author, resp.message);
+                self.push_history(author, resp.message);
+                MessageAcceptance::Accept
+            } else if let Ok(resp) = serde_json::from_slice::<SayHello>(&message.data) {
+                self.metrics.record_received("SayHello");
                 if resp.hello {
                     println!("{} has joined the chat", resp.name);
-                    self.connected.insert(msg.source.to_string(), resp.name);
-                    self.introduce(self.name.clone(), msg.source.to_string());
+                    self.connected.insert(source.clone(), resp.name);
+                    self.sync_connected_gauge();
+                    self.introduce(self.name.clone(), source_peer);
+                    if !self.history.is_empty() {
+                        self.send_history(source, None);
+                    }
                 } else {
                     println!("{} has left the chat", resp.name);
-                    self.connected.remove(&msg.source.to_string());
+                    self.connected.remove(&source);
+                    self.sync_connected_gauge();
+                }
+                MessageAcceptance::Accept
+            } else if let Ok(resp) = serde_json::from_slice::<HistoryResponse>(&message.data) {
+                if resp.receiver == (*PEER_ID).to_string() {
+                    for stored in resp.messages {
+                        println!("[history] {}: {}", stored.author, stored.text);
+                    }
+                }
+                MessageAcceptance::Accept
+            } else if let Ok(req) = serde_json::from_slice::<HistoryRequest>(&message.data) {
+                if !self.history.is_empty() {
+                    self.send_history(source, req.since);
+                }
+                MessageAcceptance::Accept
+            } else {
+                MessageAcceptance::Reject
+            };
+            let _ = self.gossipsub.report_message_validation_result(
+                &message_id,
+                &propagation_source,
+                acceptance,
+            );
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<DirectMessage, Ack>> for AppBehaviour {
+    fn inject_event(&mut self, event: RequestResponseEvent<DirectMessage, Ack>) {
+        match event {
+            RequestResponseEvent::Message { peer, message } => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                } => {
+                    if self.banned.contains(&peer) {
+                        return;
+                    }
+                    match request {
+                        DirectMessage::Chat { body } => {
+                            self.metrics.record_received("DirectMessage");
+                            let author = self.connected.get(&peer.to_string()).cloned();
+                            match author {
+                                Some(author) => println!("(direct) {}: {}", author, body),
+                                None => println!("(direct) Unknown: {}", body),
+                            }
+                        }
+                        DirectMessage::Introduce { name } => {
+                            self.metrics.record_received("IntroduceMyself");
+                            println!("{} is in chat room", name);
+                            self.connected.insert(peer.to_string(), name);
+                            self.sync_connected_gauge();
+                        }
+                    }
+                    let _ = self.direct.send_response(channel, Ack);
                 }
+                RequestResponseMessage::Response { .. } => {
+                    println!("direct message to {} was delivered", peer);
+                }
+            },
+            RequestResponseEvent::OutboundFailure {
+                peer, error, ..
+            } => {
+                println!("could not deliver direct message to {}: {:?}", peer, error);
+            }
+            RequestResponseEvent::InboundFailure {
+                peer, error, ..
+            } => {
+                println!("failed to receive direct message from {}: {:?}", peer, error);
             }
+            RequestResponseEvent::ResponseSent { .. } => {}
         }
     }
 }
 
+fn message_id_fn(message: &GossipsubMessage) -> MessageId {
+    let mut hasher = DefaultHasher::new();
+    if let Some(source) = &message.source {
+        source.hash(&mut hasher);
+    }
+    message.sequence_number.hash(&mut hasher);
+    message.data.hash(&mut hasher);
+    MessageId::from(hasher.finish().to_string())
+}
+
 impl AppBehaviour {
-    pub async fn new(name: String) -> Self {
-        let mut behaviour = AppBehaviour {
-            floodsub: Floodsub::new(*PEER_ID),
-            mdns: Mdns::new(Default::default())
+    pub async fn new(name: String, no_mdns: bool, metrics: Metrics) -> Self {
+        let gossipsub_config = GossipsubConfigBuilder::default()
+            .heartbeat_interval(Duration::from_secs(10))
+            .validation_mode(ValidationMode::Strict)
+            .validate_messages()
+            .message_id_fn(message_id_fn)
+            .build()
+            .expect("valid gossipsub config");
+        let mut gossipsub = Gossipsub::new(MessageAuthenticity::Signed(KEYS.clone()), gossipsub_config)
+            .expect("cannot create gossipsub behaviour");
+        gossipsub
+            .subscribe(&CHAT_TOPIC)
+            .expect("can subscribe to chat topic");
+
+        let direct = RequestResponse::new(
+            DirectCodec::default(),
+            iter::once((DirectProtocol::default(), ProtocolSupport::Full)),
+            RequestResponseConfig::default(),
+        );
+
+        let mdns = if no_mdns {
+            Toggle::from(None)
+        } else {
+            let mdns = Mdns::new(Default::default())
                 .await
-                .expect("cannot create mdns"),
+                .expect("cannot create mdns");
+            Toggle::from(Some(mdns))
+        };
+
+        AppBehaviour {
+            gossipsub,
+            mdns,
+            direct,
             connected: HashMap::new(),
             name,
-        };
-        behaviour.floodsub.subscribe(CHAT_TOPIC.clone());
-        behaviour
+            history: VecDeque::with_capacity(HISTORY_CAPACITY),
+            next_seq: 0,
+            banned: HashSet::new(),
+            metrics,
+        }
     }
 
     pub fn say_hello(&mut self, name: String, hello: bool) {
-        self.send(SayHello { name, hello });
+        self.send(SayHello { name, hello }, "SayHello");
     }
 
     pub fn chat(&mut self, message: String) {
-        self.send(ChatMessage { message });
+        self.push_history(self.name.clone(), message.clone());
+        self.send(ChatMessage { message }, "ChatMessage");
+    }
+
+    pub fn introduce(&mut self, name: String, receiver: PeerId) {
+        self.direct
+            .send_request(&receiver, DirectMessage::Introduce { name });
+        self.metrics.record_sent("IntroduceMyself");
+    }
+
+    pub fn request_history(&mut self, since: Option<u64>) {
+        self.send(
+            HistoryRequest {
+                since,
+                receiver: (*PEER_ID).to_string(),
+            },
+            "HistoryRequest",
+        );
+    }
+
+    fn sync_connected_gauge(&self) {
+        self.metrics.set_connected_peers(self.connected.len());
+    }
+
+    fn push_history(&mut self, author: String, text: String) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if self.history.len() == HISTORY_CAPACITY {
+            self.history.pop_front();
+        }
+        self.history.push_back(StoredMessage { author, text, seq });
+    }
+
+    fn send_history(&mut self, receiver: String, since: Option<u64>) {
+        let messages = self
+            .history
+            .iter()
+            .filter(|stored| since.map_or(true, |since| stored.seq > since))
+            .cloned()
+            .collect();
+        self.send(HistoryResponse { receiver, messages }, "HistoryResponse");
+    }
+
+    pub fn print_local_history(&self) {
+        if self.history.is_empty() {
+            println!("no local history yet");
+            return;
+        }
+        for stored in &self.history {
+            println!("[history #{}] {}: {}", stored.seq, stored.author, stored.text);
+        }
+    }
+
+    pub fn ban(&mut self, name: &str) -> bool {
+        match self.peer_by_name(name) {
+            Some(peer) => {
+                self.gossipsub.remove_explicit_peer(&peer);
+                self.banned.insert(peer);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn unban(&mut self, name: &str) -> bool {
+        match self.peer_by_name(name) {
+            Some(peer) => self.banned.remove(&peer),
+            None => false,
+        }
     }
 
-    pub fn introduce(&mut self, name: String, receiver: String) {
-        self.send(IntroduceMyself { name, receiver });
+    fn peer_by_name(&self, name: &str) -> Option<PeerId> {
+        self.connected
+            .iter()
+            .find(|(_, n)| n.as_str() == name)
+            .and_then(|(peer, _)| peer.parse::<PeerId>().ok())
     }
 
-    fn send(&mut self, msg: impl serde::ser::Serialize) {
+    pub fn direct_message(&mut self, receiver_name: &str, body: String) -> bool {
+        match self.peer_by_name(receiver_name) {
+            Some(peer) => {
+                self.direct
+                    .send_request(&peer, DirectMessage::Chat { body });
+                self.metrics.record_sent("DirectMessage");
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn send(&mut self, msg: impl serde::ser::Serialize, message_type: &str) {
         let json = serde_json::to_string(&msg).expect("can jsonify response");
-        self.floodsub.publish(CHAT_TOPIC.clone(), json.as_bytes());
+        if let Err(e) = self.gossipsub.publish(CHAT_TOPIC.clone(), json.as_bytes()) {
+            println!("cannot publish message: {:?}", e);
+        } else {
+            self.metrics.record_sent(message_type);
+        }
     }
 }
@@ -1,6 +1,7 @@
 use chat_application::*;
 use clap::Parser;
 use ctrlc;
+use std::net::SocketAddr;
 use std::process;
 use std::thread;
 use std::time::Duration;
@@ -17,16 +18,29 @@ use libp2p::{
     futures::StreamExt,
     mplex,
     noise::{Keypair, NoiseConfig, X25519Spec},
-    swarm::{Swarm, SwarmBuilder},
+    swarm::{ConnectionLimits, Swarm, SwarmBuilder, SwarmEvent},
     tcp::TokioTcpConfig,
-    Transport,
+    Multiaddr, Transport,
 };
 
+const MAX_ESTABLISHED_PER_PEER: u32 = 4;
+const MAX_ESTABLISHED_INCOMING: u32 = 64;
+const MAX_ESTABLISHED_OUTGOING: u32 = 64;
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     #[arg(short, long)]
     name: String,
+
+    #[arg(long)]
+    no_mdns: bool,
+
+    #[arg(long = "dial")]
+    dial: Vec<Multiaddr>,
+
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
 }
 
 enum EventType {
@@ -61,8 +75,27 @@ async fn main() {
         .multiplex(mplex::MplexConfig::new())
         .boxed();
 
-    let behaviour = AppBehaviour::new(args.name.clone()).await;
+    let metrics = Metrics::new();
+    if let Some(addr) = args.metrics_addr {
+        let registry = metrics.registry();
+        thread::spawn(move || {
+            let server = tiny_http::Server::http(addr).expect("can bind metrics listener");
+            for request in server.incoming_requests() {
+                let body = encode_metrics(&registry);
+                let response = tiny_http::Response::from_data(body);
+                let _ = request.respond(response);
+            }
+        });
+        println!("serving metrics on http://{}", addr);
+    }
+
+    let behaviour = AppBehaviour::new(args.name.clone(), args.no_mdns, metrics).await;
+    let connection_limits = ConnectionLimits::default()
+        .with_max_established_per_peer(Some(MAX_ESTABLISHED_PER_PEER))
+        .with_max_established_incoming(Some(MAX_ESTABLISHED_INCOMING))
+        .with_max_established_outgoing(Some(MAX_ESTABLISHED_OUTGOING));
     let mut swarm = SwarmBuilder::new(transport, behaviour, *PEER_ID)
+        .connection_limits(connection_limits)
         .executor(Box::new(|fut| {
             spawn(fut);
         }))
@@ -77,6 +110,13 @@ async fn main() {
     )
     .expect("swarm can be started");
 
+    for addr in args.dial.clone() {
+        match Swarm::dial(&mut swarm, addr.clone()) {
+            Ok(()) => println!("dialing {}", addr),
+            Err(e) => println!("could not dial {}: {:?}", addr, e),
+        }
+    }
+
     spawn(async move {
         sleep(Duration::from_secs(1)).await;
         init_sender.send(true).expect("can send init event");
@@ -94,7 +134,19 @@ async fn main() {
                 _exit = exit_rcv.recv()=>{
                     Some(EventType::Exit)
                 },
-                _event = swarm.select_next_some() =>{
+                event = swarm.select_next_some() =>{
+                    match event {
+                        SwarmEvent::NewListenAddr { address, .. } => {
+                            println!("listening on {}", address);
+                        }
+                        SwarmEvent::IncomingConnectionError { send_back_addr, error, .. } => {
+                            println!("rejected incoming connection from {}: {:?}", send_back_addr, error);
+                        }
+                        SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                            println!("could not establish connection to {:?}: {:?}", peer_id, error);
+                        }
+                        _ => {}
+                    }
                     None
                 }
             }
@@ -106,7 +158,29 @@ async fn main() {
                     swarm.behaviour_mut().say_hello(args.name.clone(), true);
                 }
                 EventType::Input(line) => {
-                    swarm.behaviour_mut().chat(line);
+                    if line.trim() == "/history" {
+                        swarm.behaviour_mut().print_local_history();
+                        swarm.behaviour_mut().request_history(None);
+                    } else if let Some(name) = line.strip_prefix("/ban ") {
+                        if !swarm.behaviour_mut().ban(name.trim()) {
+                            println!("no connected peer named \"{}\"", name.trim());
+                        }
+                    } else if let Some(name) = line.strip_prefix("/unban ") {
+                        if !swarm.behaviour_mut().unban(name.trim()) {
+                            println!("\"{}\" was not banned", name.trim());
+                        }
+                    } else if let Some(rest) = line.strip_prefix("/msg ") {
+                        match rest.split_once(' ') {
+                            Some((name, body)) => {
+                                if !swarm.behaviour_mut().direct_message(name, body.to_string()) {
+                                    println!("no connected peer named \"{}\"", name);
+                                }
+                            }
+                            None => println!("usage: /msg <peer-name> <text>"),
+                        }
+                    } else {
+                        swarm.behaviour_mut().chat(line);
+                    }
                 }
                 EventType::Exit => {
                     swarm.behaviour_mut().say_hello(args.name.clone(), false);
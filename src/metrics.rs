@@ -0,0 +1,108 @@
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    messages_sent: IntCounterVec,
+    messages_received: IntCounterVec,
+    connected_peers: IntGauge,
+    mdns_discovered: IntCounter,
+    mdns_expired: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let messages_sent = IntCounterVec::new(
+            Opts::new("chat_messages_sent_total", "messages sent, by type"),
+            &["type"],
+        )
+        .expect("valid metric");
+        let messages_received = IntCounterVec::new(
+            Opts::new("chat_messages_received_total", "messages received, by type"),
+            &["type"],
+        )
+        .expect("valid metric");
+        let connected_peers = IntGauge::new(
+            "chat_connected_peers",
+            "number of peers currently in the chat room",
+        )
+        .expect("valid metric");
+        let mdns_discovered = IntCounter::new(
+            "chat_mdns_discovered_total",
+            "peers discovered via mDNS",
+        )
+        .expect("valid metric");
+        let mdns_expired = IntCounter::new(
+            "chat_mdns_expired_total",
+            "peers expired from the mDNS cache",
+        )
+        .expect("valid metric");
+
+        registry
+            .register(Box::new(messages_sent.clone()))
+            .expect("can register metric");
+        registry
+            .register(Box::new(messages_received.clone()))
+            .expect("can register metric");
+        registry
+            .register(Box::new(connected_peers.clone()))
+            .expect("can register metric");
+        registry
+            .register(Box::new(mdns_discovered.clone()))
+            .expect("can register metric");
+        registry
+            .register(Box::new(mdns_expired.clone()))
+            .expect("can register metric");
+
+        Metrics {
+            registry,
+            messages_sent,
+            messages_received,
+            connected_peers,
+            mdns_discovered,
+            mdns_expired,
+        }
+    }
+
+    pub fn record_sent(&self, message_type: &str) {
+        self.messages_sent.with_label_values(&[message_type]).inc();
+    }
+
+    pub fn record_received(&self, message_type: &str) {
+        self.messages_received
+            .with_label_values(&[message_type])
+            .inc();
+    }
+
+    pub fn set_connected_peers(&self, count: usize) {
+        self.connected_peers.set(count as i64);
+    }
+
+    pub fn record_mdns_discovered(&self, count: usize) {
+        self.mdns_discovered.inc_by(count as u64);
+    }
+
+    pub fn record_mdns_expired(&self, count: usize) {
+        self.mdns_expired.inc_by(count as u64);
+    }
+
+    pub fn registry(&self) -> Registry {
+        self.registry.clone()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn encode(registry: &Registry) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&registry.gather(), &mut buffer)
+        .expect("can encode metrics");
+    buffer
+}